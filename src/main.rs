@@ -1,18 +1,25 @@
 use borsh::{BorshDeserialize, BorshSerialize};
+use std::borrow::Cow;
 use std::collections::{BTreeMap, HashMap};
 
-#[derive(Clone, Debug, BorshSerialize)]
-pub struct CacheImage {
+/// A loaded cache image, optionally borrowing its `code` bytes from a
+/// caller-owned buffer (e.g. a memory-mapped file) instead of copying them
+/// onto the heap. Use [`CacheImage::deserialize_borrowed`] to parse without
+/// copying `code`, or the `BorshDeserialize` impl on `CacheImage<'static>`
+/// for the owned path. `function_pointers` and `function_offsets` are
+/// `Cow`s as well, but both parsing paths decode them into owned vectors.
+#[derive(Clone, Debug)]
+pub struct CacheImage<'a> {
     /// The executable image.
-    code: Vec<u8>,
+    code: Cow<'a, [u8]>,
 
     /// Offsets to the start of each function. Including trampoline, if any.
     /// Trampolines are only present on AArch64.
     /// On x86-64, `function_pointers` are identical to `function_offsets`.
-    function_pointers: Vec<usize>,
+    function_pointers: Cow<'a, [usize]>,
 
     /// Offsets to the start of each function after trampoline.
-    function_offsets: Vec<usize>,
+    function_offsets: Cow<'a, [usize]>,
 
     /// Number of imported functions.
     func_import_count: usize,
@@ -24,6 +31,206 @@ pub struct CacheImage {
     exception_table: ExceptionTable,
 }
 
+impl<'a> CacheImage<'a> {
+    /// Upgrades a possibly-borrowed image into one that owns all of its
+    /// data, detaching it from the buffer it was parsed from.
+    pub fn into_owned(self) -> CacheImage<'static> {
+        CacheImage {
+            code: Cow::Owned(self.code.into_owned()),
+            function_pointers: Cow::Owned(self.function_pointers.into_owned()),
+            function_offsets: Cow::Owned(self.function_offsets.into_owned()),
+            func_import_count: self.func_import_count,
+            msm: self.msm,
+            exception_table: self.exception_table,
+        }
+    }
+
+    /// Zero-copy deserialization: points `code` directly into `buf` instead
+    /// of allocating and memcpying it, which is what dominates load time for
+    /// megabyte-scale images. `function_pointers` and `function_offsets` are
+    /// small by comparison and are still decoded into owned `Vec`s — `code`
+    /// bytes have no alignment requirement, but reinterpreting raw bytes as
+    /// `&[usize]` without copying would require the slice to start at a
+    /// `usize`-aligned offset into `buf`, which the preceding variable-length
+    /// `code` field makes essentially never true. `msm` and `exception_table`
+    /// are likewise decoded into owned structures since they are not laid
+    /// out as flat buffers.
+    pub fn deserialize_borrowed(buf: &'a [u8]) -> std::io::Result<Self> {
+        let mut rest = buf;
+        let code = borrow_u8_slice(&mut rest)?;
+        let function_pointers: Vec<usize> = BorshDeserialize::deserialize(&mut rest)?;
+        let function_offsets: Vec<usize> = BorshDeserialize::deserialize(&mut rest)?;
+        let func_import_count: u64 = BorshDeserialize::deserialize(&mut rest)?;
+        let func_import_count = func_import_count as usize;
+        let msm: ModuleStateMap = BorshDeserialize::deserialize(&mut rest)?;
+        let exception_table: ExceptionTable = BorshDeserialize::deserialize(&mut rest)?;
+        Ok(Self {
+            code: Cow::Borrowed(code),
+            function_pointers: Cow::Owned(function_pointers),
+            function_offsets: Cow::Owned(function_offsets),
+            func_import_count,
+            msm,
+            exception_table,
+        })
+    }
+}
+
+/// A resolved native-instruction-pointer to wasm-level frame, as produced by
+/// [`CacheImage::resolve`] — the building block for stack traces.
+#[derive(Clone, Debug)]
+pub struct ResolvedFrame {
+    /// The local function the native offset falls within.
+    pub local_function_id: usize,
+    /// The wasm instruction offset reconstructed from the replayed machine state.
+    pub wasm_inst_offset: usize,
+    /// Set when the native offset is an exact trap site.
+    pub exception_code: Option<ExceptionCode>,
+    /// The fully materialized machine state at this point.
+    pub machine_state: MachineState,
+}
+
+impl<'a> CacheImage<'a> {
+    /// Resolves a native code address (as an offset from the image base) to
+    /// a wasm-level frame: which local function it is in, the wasm
+    /// instruction offset, the reconstructed machine state, and the
+    /// exception code if the address is an exact trap site.
+    pub fn resolve(&self, native_offset: usize) -> Option<ResolvedFrame> {
+        let idx = match self.function_offsets.binary_search(&native_offset) {
+            Ok(i) => i,
+            Err(0) => return None,
+            Err(i) => i - 1,
+        };
+        if idx < self.func_import_count {
+            // Inside an imported function's trampoline; no state map.
+            return None;
+        }
+        let local_function_id = idx - self.func_import_count;
+        let fsm = self.msm.local_functions.get(&local_function_id)?;
+
+        let func_start = self.function_offsets[idx];
+        let rel_offset = native_offset - func_start;
+
+        let info = [&fsm.call_offsets, &fsm.trappable_offsets, &fsm.loop_offsets]
+            .iter()
+            .find_map(|offsets| {
+                offsets
+                    .range(..=rel_offset)
+                    .next_back()
+                    .map(|(_, info)| info)
+                    .filter(|info| rel_offset < info.end_offset)
+            })?;
+
+        let machine_state = fsm.build_state(info.diff_id).ok()?;
+        let exception_code = self.exception_table.offset_to_code.get(&native_offset).copied();
+
+        Some(ResolvedFrame {
+            local_function_id,
+            wasm_inst_offset: machine_state.wasm_inst_offset,
+            exception_code,
+            machine_state,
+        })
+    }
+}
+
+impl<'a> CacheImage<'a> {
+    /// Produces a human-readable, diffable listing of this image: one
+    /// section per local function, with every byte offset annotated with
+    /// any suspend point (loop/call/trappable, with its reconstructed wasm
+    /// instruction offset) or trap site that coincides with it. Instruction
+    /// bytes are dumped as hex since no real disassembler is wired in.
+    pub fn dump(&self) -> String {
+        use std::fmt::Write;
+        let mut out = String::new();
+
+        for (idx, &start) in self.function_offsets.iter().enumerate() {
+            if idx < self.func_import_count {
+                continue;
+            }
+            let local_function_id = idx - self.func_import_count;
+            let fsm = match self.msm.local_functions.get(&local_function_id) {
+                Some(fsm) => fsm,
+                None => continue,
+            };
+            let end = self
+                .function_offsets
+                .get(idx + 1)
+                .copied()
+                .unwrap_or(self.code.len());
+
+            writeln!(
+                out,
+                "function {} @ [{:#x}, {:#x}) shadow_size={}",
+                local_function_id, start, end, fsm.shadow_size
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "  initial: stack_values={:?} register_values={:?}",
+                fsm.initial.stack_values, fsm.initial.register_values
+            )
+            .unwrap();
+
+            for offset in start..end {
+                let rel = offset - start;
+                let mut annotations = Vec::new();
+
+                if let Some(code) = self.exception_table.offset_to_code.get(&offset) {
+                    annotations.push(format!("trap:{:?}", code));
+                }
+
+                for (label, offsets) in [
+                    ("loop", &fsm.loop_offsets),
+                    ("call", &fsm.call_offsets),
+                    ("trappable", &fsm.trappable_offsets),
+                ] {
+                    if let Some(info) = offsets.get(&rel) {
+                        let wasm_inst_offset = match fsm.build_state(info.diff_id) {
+                            Ok(state) => state.wasm_inst_offset.to_string(),
+                            Err(e) => format!("<{}>", e),
+                        };
+                        annotations.push(format!("{}@wasm+{}", label, wasm_inst_offset));
+                    }
+                }
+
+                write!(out, "  {:#06x}: {:02x}", offset, self.code[offset]).unwrap();
+                if !annotations.is_empty() {
+                    write!(out, "  ; {}", annotations.join(", ")).unwrap();
+                }
+                writeln!(out).unwrap();
+            }
+        }
+
+        out
+    }
+}
+
+impl<'a> BorshSerialize for CacheImage<'a> {
+    fn serialize<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        BorshSerialize::serialize(self.code.as_ref(), writer)?;
+        BorshSerialize::serialize(self.function_pointers.as_ref(), writer)?;
+        BorshSerialize::serialize(self.function_offsets.as_ref(), writer)?;
+        BorshSerialize::serialize(&(self.func_import_count as u64), writer)?;
+        BorshSerialize::serialize(&self.msm, writer)?;
+        BorshSerialize::serialize(&self.exception_table, writer)
+    }
+}
+
+/// Reads a borsh length prefix and returns a borrowed `&[u8]` slice of the
+/// following bytes without copying, advancing `buf` past it.
+fn borrow_u8_slice<'b>(buf: &mut &'b [u8]) -> std::io::Result<&'b [u8]> {
+    let len: u32 = BorshDeserialize::deserialize(buf)?;
+    let len = len as usize;
+    if buf.len() < len {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "buffer too short for borrowed byte slice",
+        ));
+    }
+    let (slice, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(slice)
+}
+
 #[derive(Clone, Debug, BorshSerialize, BorshDeserialize)]
 pub struct ModuleStateMap {
     /// Local functions.
@@ -32,6 +239,105 @@ pub struct ModuleStateMap {
     pub total_size: usize,
 }
 
+impl FunctionStateMap {
+    /// Reconstructs the full `MachineState` at `diff_id` by replaying the
+    /// chain of diffs from the root (the diff whose `last` is `None`) down
+    /// to `diff_id`, starting from `self.initial`.
+    pub fn build_state(&self, diff_id: usize) -> Result<MachineState, String> {
+        if diff_id >= self.diffs.len() {
+            return Err(format!(
+                "diff_id {} out of bounds (have {} diffs)",
+                diff_id,
+                self.diffs.len()
+            ));
+        }
+
+        // Walk `last` links back to the root, collecting diff indices along
+        // the way. `seen` guards against a corrupted or malicious diff chain
+        // (this data may come from an untrusted on-disk image) that is
+        // out-of-bounds or cyclic instead of terminating at a root.
+        let mut chain = vec![diff_id];
+        let mut seen = std::collections::HashSet::new();
+        seen.insert(diff_id);
+        let mut cur = diff_id;
+        while let Some(last) = self.diffs[cur].last {
+            if last >= self.diffs.len() {
+                return Err(format!(
+                    "diff {} has out-of-bounds parent {} (have {} diffs)",
+                    cur,
+                    last,
+                    self.diffs.len()
+                ));
+            }
+            if !seen.insert(last) {
+                return Err(format!("cyclic diff chain detected at diff {}", last));
+            }
+            chain.push(last);
+            cur = last;
+        }
+
+        let mut state = self.initial.clone();
+        // Apply diffs in root -> leaf order.
+        for &idx in chain.iter().rev() {
+            let diff = &self.diffs[idx];
+
+            let stack_len = state.stack_values.len();
+            if diff.stack_pop > stack_len {
+                return Err(format!(
+                    "stack_pop {} exceeds stack_values length {} at diff {}",
+                    diff.stack_pop, stack_len, idx
+                ));
+            }
+            state
+                .stack_values
+                .truncate(stack_len - diff.stack_pop);
+            state.stack_values.extend(diff.stack_push.iter().cloned());
+
+            for (RegisterIndex(i), v) in &diff.reg_diff {
+                let reg = state.register_values.get_mut(*i).ok_or_else(|| {
+                    format!(
+                        "reg_diff index {} out of bounds (register_values has {} entries) at diff {}",
+                        i,
+                        state.register_values.len(),
+                        idx
+                    )
+                })?;
+                *reg = v.clone();
+            }
+
+            for (k, v) in &diff.prev_frame_diff {
+                match v {
+                    Some(v) => {
+                        state.prev_frame.insert(*k, v.clone());
+                    }
+                    None => {
+                        state.prev_frame.remove(k);
+                    }
+                }
+            }
+
+            let wasm_stack_len = state.wasm_stack.len();
+            if diff.wasm_stack_pop > wasm_stack_len {
+                return Err(format!(
+                    "wasm_stack_pop {} exceeds wasm_stack length {} at diff {}",
+                    diff.wasm_stack_pop, wasm_stack_len, idx
+                ));
+            }
+            state
+                .wasm_stack
+                .truncate(wasm_stack_len - diff.wasm_stack_pop);
+            state
+                .wasm_stack
+                .extend(diff.wasm_stack_push.iter().cloned());
+
+            state.wasm_stack_private_depth = diff.wasm_stack_private_depth;
+            state.wasm_inst_offset = diff.wasm_inst_offset;
+        }
+
+        Ok(state)
+    }
+}
+
 #[derive(Clone, Debug, BorshSerialize, BorshDeserialize)]
 pub struct FunctionStateMap {
     /// Initial.
@@ -216,7 +522,25 @@ pub enum SuspendOffset {
     Trappable(usize),
 }
 
-#[derive(Clone, Debug, Default, BorshDeserialize)]
+/// Wire-format magic for `ExceptionTable`'s custom (de)serialization.
+const EXCEPTION_TABLE_MAGIC: [u8; 4] = *b"EXT1";
+/// Bumped whenever the on-disk layout of `ExceptionTable` changes.
+const EXCEPTION_TABLE_FORMAT_VERSION: u8 = 1;
+
+/// Which body follows the `ExceptionTable` header.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum ExceptionTableEncoding {
+    /// Sorted `(offset: u64, code: u8)` pairs. Portable across hashbrown
+    /// versions, pointer widths, and endianness; always decodable.
+    Portable = 0,
+    /// The occupied buckets read out of hashbrown's internal `RawTable`,
+    /// with the table's shape fields in front. Only decodable when the
+    /// recorded format version and pointer width match the running build;
+    /// see [`ExceptionTable::serialize_fast`].
+    Fast = 1,
+}
+
+#[derive(Clone, Debug, Default)]
 pub struct ExceptionTable {
     /// Mappings from offsets in generated machine code to the corresponding exception code.
     pub offset_to_code: HashMap<usize, ExceptionCode>,
@@ -248,14 +572,14 @@ pub struct OffsetInfo {
     pub activate_offset: usize,
 }
 
-impl BorshDeserialize for CacheImage {
+impl BorshDeserialize for CacheImage<'static> {
     fn deserialize(buf: &mut &[u8]) -> std::io::Result<Self> {
         let t1 = std::time::Instant::now();
         let code: Vec<u8> = BorshDeserialize::deserialize(buf)?;
         let t2 = std::time::Instant::now();
-        let function_pointers = BorshDeserialize::deserialize(buf)?;
+        let function_pointers: Vec<usize> = BorshDeserialize::deserialize(buf)?;
         let t3 = std::time::Instant::now();
-        let function_offsets = BorshDeserialize::deserialize(buf)?;
+        let function_offsets: Vec<usize> = BorshDeserialize::deserialize(buf)?;
         let t4 = std::time::Instant::now();
         let func_import_count: u64 = BorshDeserialize::deserialize(buf)?;
         let t5 = std::time::Instant::now();
@@ -276,9 +600,9 @@ impl BorshDeserialize for CacheImage {
             t8 - t7
         );
         Ok(Self {
-            code,
-            function_pointers,
-            function_offsets,
+            code: Cow::Owned(code),
+            function_pointers: Cow::Owned(function_pointers),
+            function_offsets: Cow::Owned(function_offsets),
             func_import_count,
             msm,
             exception_table,
@@ -297,7 +621,8 @@ fn main() {
         // println!("{:?}", buffer);
     }
     let t1 = std::time::Instant::now();
-    let cache_image: CacheImage = BorshDeserialize::deserialize(&mut buffer.as_ref()).unwrap();
+    let cache_image: CacheImage<'static> =
+        BorshDeserialize::deserialize(&mut buffer.as_ref()).unwrap();
     let t2 = std::time::Instant::now();
     println!("{:?} {:?}", t2 - t1, cache_image.code.len());
 }
@@ -323,20 +648,336 @@ pub struct RawTable<T> {
 }
 
 use std::mem;
-impl BorshSerialize for ExceptionTable {
-    fn serialize<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
-        let mut std_hashmap: HashMap2<usize, ExceptionCode> = unsafe {
-            mem::transmute_copy(&self.offset_to_code)
-        };
-        let mut hashbrown_raw_table = std_hashmap.base.table;
+
+impl ExceptionTable {
+    fn write_header<W: std::io::Write>(
+        writer: &mut W,
+        encoding: ExceptionTableEncoding,
+    ) -> std::io::Result<()> {
+        writer.write_all(&EXCEPTION_TABLE_MAGIC)?;
+        BorshSerialize::serialize(&EXCEPTION_TABLE_FORMAT_VERSION, writer)?;
+        BorshSerialize::serialize(&(std::mem::size_of::<usize>() as u8), writer)?;
+        BorshSerialize::serialize(&(encoding as u8), writer)
+    }
+
+    /// Serializes via a walk of hashbrown's internal `RawTable`, the way
+    /// this type used to serialize unconditionally. This is an opt-in fast
+    /// path: it reads the map through the hand-mirrored `RawTable` layout
+    /// above, so it silently produces garbage if the std build doing the
+    /// encoding lays out its hashbrown table differently. The decoder
+    /// rejects a fast-path table whose recorded pointer width differs from
+    /// its own. Prefer the portable encoding (used by the `BorshSerialize`
+    /// impl) unless you control both ends of the round trip.
+    pub fn serialize_fast<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        Self::write_header(writer, ExceptionTableEncoding::Fast)?;
+        let std_hashmap: HashMap2<usize, ExceptionCode> =
+            unsafe { mem::transmute_copy(&self.offset_to_code) };
+        let hashbrown_raw_table = std_hashmap.base.table;
         BorshSerialize::serialize(&hashbrown_raw_table.bucket_mask, writer)?;
         BorshSerialize::serialize(&hashbrown_raw_table.growth_left, writer)?;
         BorshSerialize::serialize(&hashbrown_raw_table.items, writer)?;
-        let buckets = hashbrown_raw_table.bucket_mask+1;
-        let mut data_start = unsafe{NonNull::new_unchecked(hashbrown_raw_table.ctrl.as_ptr() as *mut (usize, ExceptionCode)).as_ptr().wrapping_sub(buckets)};
-        BorshSerialize::serialize(&unsafe{*std::ptr::slice_from_raw_parts(data_start, buckets+buckets+16)}, writer)
+        let buckets = hashbrown_raw_table.bucket_mask + 1;
+        let ctrl = hashbrown_raw_table.ctrl.as_ptr();
+        // hashbrown stores the buckets just below the control bytes in
+        // reverse order: bucket `i` lives at `data_end - (i + 1)`.
+        let data_end = ctrl as *const (usize, ExceptionCode);
+        // A raw memcpy of every bucket slot — including the ones hashbrown's
+        // control bytes mark empty — reads uninitialized memory, and it is
+        // UB (crashes in practice, since the table is essentially never
+        // completely full) to even match on that garbage as an
+        // `ExceptionCode`. Only copy the occupied slots: hashbrown clears a
+        // control byte's top bit for a full bucket and sets it (0x80) for
+        // empty/deleted ones.
+        let occupied: Vec<(usize, ExceptionCode)> = (0..buckets)
+            .filter(|&i| unsafe { *ctrl.add(i) } & 0x80 == 0)
+            .map(|i| unsafe { *data_end.sub(i + 1) })
+            .collect();
+        BorshSerialize::serialize(&occupied, writer)
+    }
+}
+
+impl BorshSerialize for ExceptionTable {
+    fn serialize<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        Self::write_header(writer, ExceptionTableEncoding::Portable)?;
+        let mut entries: Vec<(u64, u8)> = self
+            .offset_to_code
+            .iter()
+            .map(|(offset, code)| (*offset as u64, *code as u8))
+            .collect();
+        entries.sort_unstable_by_key(|(offset, _)| *offset);
+        BorshSerialize::serialize(&(entries.len() as u64), writer)?;
+        for (offset, code) in entries {
+            BorshSerialize::serialize(&offset, writer)?;
+            writer.write_all(&[code])?;
+        }
+        Ok(())
+    }
+}
+
+impl BorshDeserialize for ExceptionTable {
+    fn deserialize(buf: &mut &[u8]) -> std::io::Result<Self> {
+        if buf.len() < EXCEPTION_TABLE_MAGIC.len() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "exception table: truncated header",
+            ));
+        }
+        let (magic, rest) = buf.split_at(EXCEPTION_TABLE_MAGIC.len());
+        if magic != EXCEPTION_TABLE_MAGIC {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "exception table: bad magic",
+            ));
+        }
+        *buf = rest;
+        let format_version: u8 = BorshDeserialize::deserialize(buf)?;
+        let pointer_width: u8 = BorshDeserialize::deserialize(buf)?;
+        let encoding: u8 = BorshDeserialize::deserialize(buf)?;
 
+        if format_version != EXCEPTION_TABLE_FORMAT_VERSION {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "exception table: unsupported format version {}",
+                    format_version
+                ),
+            ));
+        }
+
+        match encoding {
+            0 => {
+                let len: u64 = BorshDeserialize::deserialize(buf)?;
+                let mut offset_to_code = HashMap::with_capacity(len as usize);
+                for _ in 0..len {
+                    let offset: u64 = BorshDeserialize::deserialize(buf)?;
+                    let code: u8 = BorshDeserialize::deserialize(buf)?;
+                    offset_to_code.insert(offset as usize, exception_code_from_u8(code)?);
+                }
+                Ok(ExceptionTable { offset_to_code })
+            }
+            1 => {
+                if pointer_width as usize != std::mem::size_of::<usize>() {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "exception table: fast-path pointer width mismatch, re-encode with the portable format",
+                    ));
+                }
+                // `bucket_mask`/`growth_left` are the raw `RawTable` shape at
+                // encode time; kept only as diagnostic metadata. The actual
+                // payload is the `(offset, code)` pairs `serialize_fast`
+                // collected from occupied buckets, which is what `items`
+                // below is checked against.
+                let _bucket_mask: usize = BorshDeserialize::deserialize(buf)?;
+                let _growth_left: usize = BorshDeserialize::deserialize(buf)?;
+                let items: usize = BorshDeserialize::deserialize(buf)?;
+                let occupied: Vec<(usize, ExceptionCode)> = BorshDeserialize::deserialize(buf)?;
+                if occupied.len() != items {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "exception table: fast-path entry count mismatch",
+                    ));
+                }
+                let mut offset_to_code = HashMap::with_capacity(occupied.len());
+                for (offset, code) in occupied {
+                    offset_to_code.insert(offset, code);
+                }
+                Ok(ExceptionTable { offset_to_code })
+            }
+            other => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("exception table: unknown encoding byte {}", other),
+            )),
+        }
     }
 }
 
+fn exception_code_from_u8(code: u8) -> std::io::Result<ExceptionCode> {
+    Ok(match code {
+        0 => ExceptionCode::Unreachable,
+        1 => ExceptionCode::IncorrectCallIndirectSignature,
+        2 => ExceptionCode::MemoryOutOfBounds,
+        3 => ExceptionCode::CallIndirectOOB,
+        4 => ExceptionCode::IllegalArithmetic,
+        5 => ExceptionCode::MisalignedAtomicAccess,
+        other => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("exception table: unknown exception code {}", other),
+            ))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local_value() -> MachineValue {
+        MachineValue::WasmLocal(0)
+    }
+
+    fn empty_function_state_map(diffs: Vec<MachineStateDiff>) -> FunctionStateMap {
+        FunctionStateMap {
+            initial: MachineState {
+                stack_values: vec![],
+                register_values: vec![],
+                prev_frame: BTreeMap::new(),
+                wasm_stack: vec![],
+                wasm_stack_private_depth: 0,
+                wasm_inst_offset: 0,
+            },
+            local_function_id: 0,
+            locals: vec![],
+            shadow_size: 0,
+            diffs,
+            wasm_function_header_target_offset: None,
+            wasm_offset_to_target_offset: BTreeMap::new(),
+            loop_offsets: BTreeMap::new(),
+            call_offsets: BTreeMap::new(),
+            trappable_offsets: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn build_state_replays_diff_chain() {
+        let mut fsm = empty_function_state_map(vec![
+            MachineStateDiff {
+                last: None,
+                stack_push: vec![local_value()],
+                stack_pop: 0,
+                reg_diff: vec![(RegisterIndex(0), MachineValue::Vmctx)],
+                prev_frame_diff: BTreeMap::new(),
+                wasm_stack_push: vec![],
+                wasm_stack_pop: 0,
+                wasm_stack_private_depth: 1,
+                wasm_inst_offset: 10,
+            },
+            MachineStateDiff {
+                last: Some(0),
+                stack_push: vec![],
+                stack_pop: 1,
+                reg_diff: vec![(RegisterIndex(1), MachineValue::Vmctx)],
+                prev_frame_diff: {
+                    let mut m = BTreeMap::new();
+                    m.insert(0usize, Some(local_value()));
+                    m
+                },
+                wasm_stack_push: vec![WasmAbstractValue::Runtime],
+                wasm_stack_pop: 0,
+                wasm_stack_private_depth: 2,
+                wasm_inst_offset: 20,
+            },
+        ]);
+        fsm.initial.stack_values = vec![local_value()];
+        fsm.initial.register_values = vec![MachineValue::Undefined; 2];
+        fsm.initial.wasm_stack = vec![WasmAbstractValue::Const(1)];
+
+        let state = fsm.build_state(1).unwrap();
+        assert_eq!(state.stack_values.len(), 1);
+        assert_eq!(
+            state.register_values,
+            vec![MachineValue::Vmctx, MachineValue::Vmctx]
+        );
+        assert_eq!(state.prev_frame.get(&0), Some(&local_value()));
+        assert_eq!(state.wasm_stack.len(), 2);
+        assert_eq!(state.wasm_stack_private_depth, 2);
+        assert_eq!(state.wasm_inst_offset, 20);
+    }
+
+    #[test]
+    fn build_state_rejects_out_of_bounds_diff_id() {
+        let fsm = empty_function_state_map(vec![MachineStateDiff::default()]);
+        assert!(fsm.build_state(5).is_err());
+    }
+
+    #[test]
+    fn build_state_rejects_out_of_bounds_register_index() {
+        // `register_values` starts empty; a `reg_diff` targeting any index
+        // must error instead of panicking on an untrusted diff chain.
+        let fsm = empty_function_state_map(vec![MachineStateDiff {
+            reg_diff: vec![(RegisterIndex(5), MachineValue::Vmctx)],
+            ..Default::default()
+        }]);
+        assert!(fsm.build_state(0).is_err());
+    }
+
+    #[test]
+    fn build_state_rejects_cyclic_chain() {
+        let fsm = empty_function_state_map(vec![
+            MachineStateDiff {
+                last: Some(1),
+                ..Default::default()
+            },
+            MachineStateDiff {
+                last: Some(0),
+                ..Default::default()
+            },
+        ]);
+        assert!(fsm.build_state(0).is_err());
+    }
+
+    #[test]
+    fn deserialize_borrowed_round_trips_and_borrows_code() {
+        let image = CacheImage {
+            code: Cow::Owned(vec![1u8, 2, 3, 4, 5]),
+            function_pointers: Cow::Owned(vec![0usize, 5]),
+            function_offsets: Cow::Owned(vec![0usize, 5]),
+            func_import_count: 0,
+            msm: ModuleStateMap {
+                local_functions: BTreeMap::new(),
+                total_size: 5,
+            },
+            exception_table: ExceptionTable::default(),
+        };
+
+        let mut buf = Vec::new();
+        BorshSerialize::serialize(&image, &mut buf).unwrap();
+
+        let borrowed = CacheImage::deserialize_borrowed(&buf).unwrap();
+        assert!(matches!(borrowed.code, Cow::Borrowed(_)));
+        assert_eq!(borrowed.code.as_ref(), &[1u8, 2, 3, 4, 5][..]);
+        assert_eq!(borrowed.function_pointers.as_ref(), &[0usize, 5][..]);
+        assert_eq!(borrowed.function_offsets.as_ref(), &[0usize, 5][..]);
+        assert_eq!(borrowed.func_import_count, 0);
+    }
+
+    #[test]
+    fn exception_table_portable_round_trips() {
+        let mut offset_to_code = HashMap::new();
+        offset_to_code.insert(42usize, ExceptionCode::MemoryOutOfBounds);
+        offset_to_code.insert(7usize, ExceptionCode::Unreachable);
+        let table = ExceptionTable { offset_to_code };
+
+        let mut buf = Vec::new();
+        BorshSerialize::serialize(&table, &mut buf).unwrap();
+
+        let decoded = ExceptionTable::deserialize(&mut buf.as_slice()).unwrap();
+        assert_eq!(decoded.offset_to_code, table.offset_to_code);
+    }
+
+    #[test]
+    fn exception_table_rejects_bad_magic() {
+        let buf = vec![0u8; 16];
+        assert!(ExceptionTable::deserialize(&mut buf.as_slice()).is_err());
+    }
+
+    #[test]
+    fn exception_table_fast_path_round_trips_when_not_full() {
+        // A freshly-inserted-into HashMap keeps spare capacity (hashbrown
+        // never fills a table to 100%), so this exercises the common case
+        // where `serialize_fast` must skip empty buckets rather than
+        // memcpy-ing the whole bucket array.
+        let mut offset_to_code = HashMap::new();
+        for i in 0..20usize {
+            offset_to_code.insert(i, ExceptionCode::Unreachable);
+        }
+        let table = ExceptionTable { offset_to_code };
+
+        let mut buf = Vec::new();
+        table.serialize_fast(&mut buf).unwrap();
+
+        let decoded = ExceptionTable::deserialize(&mut buf.as_slice()).unwrap();
+        assert_eq!(decoded.offset_to_code, table.offset_to_code);
+    }
+}
 